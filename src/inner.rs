@@ -1,21 +1,113 @@
+use crate::signal::LoadSignal;
 use rand::prelude::*;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Weak;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, Weak};
 use std::time::Instant;
-use tokio::time::{interval_at, Duration};
+use tokio::time::{interval_at, Duration, Interval};
+
+/// Prometheus collectors wired into a [`TooBusyShared`] so operators can scrape the
+/// shedding behavior instead of logging every request.
+///
+/// The handle lives in the shared state: [`TooBusyShared::eval`] bumps the counters and the
+/// [`LoadFeeder`] sets the gauge through its reference to that state. Every collector is
+/// internally reference-counted, so a clone of the handle updates the same series.
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    ratio_busy_ewma: prometheus::Gauge,
+    shed: prometheus::IntCounter,
+    admitted: prometheus::IntCounter,
+}
+
+#[cfg(feature = "prometheus")]
+impl Metrics {
+    /// Build the collectors under `namespace` and register them into `registry`.
+    pub(crate) fn register(
+        registry: &prometheus::Registry,
+        namespace: &str,
+    ) -> prometheus::Result<Self> {
+        let ratio_busy_ewma = prometheus::Gauge::with_opts(
+            prometheus::Opts::new(
+                "ratio_busy_ewma",
+                "Busy ratio of the Tokio workers tracked through an exponential moving average.",
+            )
+            .namespace(namespace.to_owned()),
+        )?;
+        let shed = prometheus::IntCounter::with_opts(
+            prometheus::Opts::new(
+                "shed_total",
+                "Number of evaluations that rejected the request because the workers were too busy.",
+            )
+            .namespace(namespace.to_owned()),
+        )?;
+        let admitted = prometheus::IntCounter::with_opts(
+            prometheus::Opts::new(
+                "admitted_total",
+                "Number of evaluations that admitted the request.",
+            )
+            .namespace(namespace.to_owned()),
+        )?;
+
+        registry.register(Box::new(ratio_busy_ewma.clone()))?;
+        registry.register(Box::new(shed.clone()))?;
+        registry.register(Box::new(admitted.clone()))?;
+
+        Ok(Metrics {
+            ratio_busy_ewma,
+            shed,
+            admitted,
+        })
+    }
+}
+
+/// Strategy used by [`TooBusyShared::eval`] to decide when to shed.
+///
+/// The default [`Watermark`](Mode::Watermark) mode ramps probabilistically between the low and
+/// high watermarks of the busy ratio, while [`Codel`](Mode::Codel) targets the scheduling delay
+/// clients actually experience.
+pub(crate) enum Mode {
+    Watermark {
+        low_watermark: u32,
+        high_watermark: u32,
+    },
+    Codel(Codel),
+}
 
 pub(crate) struct TooBusyShared {
-    low_watermark: u32,
-    high_watermark: u32,
+    mode: Mode,
     ratio_busy_ewma: AtomicU32,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Metrics>,
 }
 
 impl TooBusyShared {
-    pub(crate) fn new(low_watermark: u32, high_watermark: u32) -> Self {
+    pub(crate) fn new(
+        low_watermark: u32,
+        high_watermark: u32,
+        #[cfg(feature = "prometheus")] metrics: Option<Metrics>,
+    ) -> Self {
         TooBusyShared {
-            low_watermark,
-            high_watermark,
+            mode: Mode::Watermark {
+                low_watermark,
+                high_watermark,
+            },
             ratio_busy_ewma: AtomicU32::new(0),
+            #[cfg(feature = "prometheus")]
+            metrics,
+        }
+    }
+
+    pub(crate) fn new_codel(
+        target: Duration,
+        interval: Duration,
+        #[cfg(feature = "prometheus")] metrics: Option<Metrics>,
+    ) -> Self {
+        TooBusyShared {
+            mode: Mode::Codel(Codel::new(target, interval)),
+            ratio_busy_ewma: AtomicU32::new(0),
+            #[cfg(feature = "prometheus")]
+            metrics,
         }
     }
 
@@ -24,24 +116,49 @@ impl TooBusyShared {
     }
 
     pub(crate) fn eval(&self) -> bool {
-        let ratio_busy_ewma = self.ratio_busy_ewma.load(Ordering::Relaxed);
-        self.calculate_proabilistic_too_busy(&mut rand::thread_rng(), ratio_busy_ewma)
+        let too_busy = match &self.mode {
+            Mode::Watermark { .. } => {
+                let ratio_busy_ewma = self.ratio_busy_ewma.load(Ordering::Relaxed);
+                self.calculate_proabilistic_too_busy(&mut rand::thread_rng(), ratio_busy_ewma)
+            }
+            Mode::Codel(codel) => codel.eval(Instant::now()),
+        };
+
+        #[cfg(feature = "prometheus")]
+        if let Some(metrics) = &self.metrics {
+            if too_busy {
+                metrics.shed.inc();
+            } else {
+                metrics.admitted.inc();
+            }
+        }
+
+        too_busy
     }
 
     fn calculate_proabilistic_too_busy<A: Rng>(&self, rng: &mut A, ratio_busy_ewma: u32) -> bool {
-        if ratio_busy_ewma < self.low_watermark {
+        let (low_watermark, high_watermark) = match &self.mode {
+            Mode::Watermark {
+                low_watermark,
+                high_watermark,
+            } => (*low_watermark, *high_watermark),
+            // CoDel mode never routes through the watermark ramp.
+            Mode::Codel(_) => return false,
+        };
+
+        if ratio_busy_ewma < low_watermark {
             return false;
-        } else if ratio_busy_ewma >= self.high_watermark {
+        } else if ratio_busy_ewma >= high_watermark {
             return true;
         }
 
         // we are in the middle of the low and high
         // watermark, we will return too busy progressivelly
         // from [0, 99] % depending on value of the current load.
-        let max_range = self.high_watermark - self.low_watermark;
+        let max_range = high_watermark - low_watermark;
 
         // tell us the percentage of calls that would need to return too busy.
-        let percentage = ((ratio_busy_ewma - self.low_watermark) * 100 / max_range) as u32;
+        let percentage = ((ratio_busy_ewma - low_watermark) * 100 / max_range) as u32;
 
         if rng.gen::<u32>() % 100 < percentage {
             return true;
@@ -51,63 +168,284 @@ impl TooBusyShared {
     }
 }
 
-pub(crate) struct LoadFeeder {
+/// CoDel (controlled delay) shedding state.
+///
+/// Instead of ramping on CPU-busy ratio, CoDel tracks the scheduling delay tasks experience and
+/// only sheds once the minimum delay observed over a full `interval` stays above `target`. While
+/// overloaded it drops at an accelerating cadence of `interval / sqrt(count)`, which behaves
+/// better than a linear ramp under bursty backlogs.
+///
+/// The windowed minimum latency is maintained off the hot path by [`CodelProbe`] and published
+/// through an atomic; [`eval`](Codel::eval) only loads that atomic and takes the small
+/// [`CodelVars`] lock to advance the drop cadence, so request evaluation stays cheap even under
+/// overload.
+pub(crate) struct Codel {
+    /// Windowed minimum scheduling latency in microseconds, published by [`CodelProbe`].
+    min_latency_micros: AtomicU64,
+    vars: Mutex<CodelVars>,
+}
+
+struct CodelVars {
+    target: Duration,
+    interval: Duration,
+    dropping: bool,
+    count: u32,
+    first_above_target: Option<Instant>,
+    drop_next: Instant,
+}
+
+impl Codel {
+    pub(crate) fn new(target: Duration, interval: Duration) -> Self {
+        Codel {
+            min_latency_micros: AtomicU64::new(0),
+            vars: Mutex::new(CodelVars {
+                target,
+                interval,
+                dropping: false,
+                count: 0,
+                first_above_target: None,
+                drop_next: Instant::now(),
+            }),
+        }
+    }
+
+    /// Publish the windowed minimum latency computed by the probe.
+    pub(crate) fn publish_min_latency(&self, min_latency: Duration) {
+        self.min_latency_micros
+            .store(min_latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Decide whether the request evaluated at `now` should be shed.
+    fn eval(&self, now: Instant) -> bool {
+        let min_latency = Duration::from_micros(self.min_latency_micros.load(Ordering::Relaxed));
+        let mut vars = self.vars.lock().unwrap();
+        vars.should_drop(now, min_latency)
+    }
+}
+
+impl CodelVars {
+    /// Control law: update the dropping state for the request evaluated at `now` given the
+    /// windowed `min_latency`, returning `true` when the request should be shed.
+    fn should_drop(&mut self, now: Instant, min_latency: Duration) -> bool {
+        // As soon as the queue drains below target we leave dropping state and reset the cadence.
+        if min_latency < self.target {
+            self.first_above_target = None;
+            self.dropping = false;
+            self.count = 0;
+            return false;
+        }
+
+        // Latency is above target; require it to stay there for a full interval before dropping.
+        match self.first_above_target {
+            None => {
+                self.first_above_target = Some(now + self.interval);
+                return false;
+            }
+            Some(above_since) if now < above_since => return false,
+            Some(_) => {}
+        }
+
+        if !self.dropping {
+            self.dropping = true;
+            self.count = 1;
+            self.drop_next = now + self.control_interval();
+            return true;
+        }
+
+        if now >= self.drop_next {
+            self.count += 1;
+            self.drop_next = now + self.control_interval();
+            return true;
+        }
+
+        false
+    }
+
+    /// Time until the next drop: `interval / sqrt(count)`.
+    fn control_interval(&self) -> Duration {
+        Duration::from_secs_f32(self.interval.as_secs_f32() / (self.count as f32).sqrt())
+    }
+}
+
+/// Clock driving the [`LoadFeeder`] loop.
+///
+/// The production [`IntervalClock`] ticks a real [`Interval`], while tests can supply a clock that
+/// advances synthetic time deterministically.
+pub(crate) trait Clock: Send + 'static {
+    /// Resolve once the next sampling interval has elapsed.
+    async fn tick(&mut self);
+}
+
+/// Production [`Clock`] ticking a real Tokio [`Interval`].
+pub(crate) struct IntervalClock {
+    period: Duration,
+    interval: Option<Interval>,
+}
+
+impl IntervalClock {
+    pub(crate) fn new(period: Duration) -> Self {
+        IntervalClock {
+            period,
+            interval: None,
+        }
+    }
+}
+
+impl Clock for IntervalClock {
+    async fn tick(&mut self) {
+        // The interval is created on the first tick, so building the clock needs no runtime.
+        let period = self.period;
+        let interval = self.interval.get_or_insert_with(|| {
+            let start = Instant::now() + period;
+            interval_at(start.into(), period)
+        });
+        interval.tick().await;
+    }
+}
+
+pub(crate) struct LoadFeeder<C: Clock = IntervalClock> {
     pub(crate) inner: Weak<TooBusyShared>,
     interval: Duration,
     ewma_alpha: f32,
+    signal: Box<dyn LoadSignal>,
+    clock: C,
+}
+
+impl LoadFeeder<IntervalClock> {
+    pub(crate) fn new(
+        inner: Weak<TooBusyShared>,
+        interval: Duration,
+        ewma_alpha: f32,
+        signal: Box<dyn LoadSignal>,
+    ) -> Self {
+        let clock = IntervalClock::new(interval);
+        LoadFeeder::with_clock(inner, interval, ewma_alpha, signal, clock)
+    }
 }
 
-impl LoadFeeder {
-    pub(crate) fn new(inner: Weak<TooBusyShared>, interval: Duration, ewma_alpha: f32) -> Self {
+impl<C: Clock> LoadFeeder<C> {
+    pub(crate) fn with_clock(
+        inner: Weak<TooBusyShared>,
+        interval: Duration,
+        ewma_alpha: f32,
+        signal: Box<dyn LoadSignal>,
+        clock: C,
+    ) -> Self {
         LoadFeeder {
             inner,
             interval,
             ewma_alpha,
+            signal,
+            clock,
         }
     }
 
-    pub(crate) async fn run(&self) {
-        let metrics = tokio::runtime::Handle::current().metrics();
-        let num_workers = metrics.num_workers() as u32;
-        let start = Instant::now() + self.interval;
-        let mut interval = interval_at(start.into(), self.interval);
+    pub(crate) async fn run(&mut self) {
         let mut ratio_busy_ewma: f32 = 0.0;
-        let mut latest_total_busy_accumulated = ((0..num_workers as usize)
-            .map(|worker| metrics.worker_total_busy_duration(worker))
-            .sum::<Duration>()
-            / num_workers).as_millis();
 
         loop {
-            let _ = interval.tick().await;
+            self.clock.tick().await;
             let too_busy_shared = match self.inner.upgrade() {
                 Some(inner) => inner,
                 None => break,
             };
 
-            let total_busy_accumulated = ((0..num_workers as usize)
-                .map(|worker| metrics.worker_total_busy_duration(worker))
-                .sum::<Duration>()
-                / num_workers).as_millis();
+            ratio_busy_ewma = self.step(&too_busy_shared, ratio_busy_ewma);
+        }
+    }
+
+    /// Run a single iteration of the feedback loop: sample the signal, fold it into the EWMA and
+    /// publish the result. Returns the updated busy ratio so the caller can thread it into the
+    /// next iteration.
+    fn step(&self, too_busy_shared: &TooBusyShared, ratio_busy_ewma: f32) -> f32 {
+        let ratio_busy = self.signal.sample(self.interval);
+        let ratio_busy_ewma = self.calculate_ratio_busy_ewma(ratio_busy, ratio_busy_ewma);
 
-            let total_busy_since_last_iteration = total_busy_accumulated - latest_total_busy_accumulated;
-            latest_total_busy_accumulated = total_busy_accumulated;
-            ratio_busy_ewma = self.calculate_ratio_busy_ewma(total_busy_since_last_iteration, ratio_busy_ewma);
+        too_busy_shared
+            .ratio_busy_ewma
+            .store(ratio_busy_ewma as u32, Ordering::Relaxed);
 
-            too_busy_shared
-                .ratio_busy_ewma
-                .store(ratio_busy_ewma as u32, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        if let Some(metrics) = &too_busy_shared.metrics {
+            metrics.ratio_busy_ewma.set(ratio_busy_ewma as f64);
         }
+
+        ratio_busy_ewma
     }
 
-    fn calculate_ratio_busy_ewma(&self, busy: u128, ratio_busy_ewma: f32) -> f32 {
-        let ratio_busy = f32::min(
-            100.0,
-            (busy as f32 / self.interval.as_millis() as f32) * 100.0,
-        );
+    fn calculate_ratio_busy_ewma(&self, ratio_busy: f32, ratio_busy_ewma: f32) -> f32 {
         (self.ewma_alpha * ratio_busy_ewma) + ((1.0 - self.ewma_alpha) * ratio_busy)
     }
+}
 
-    
+/// Background probe feeding scheduling-delay samples into a [`Codel`] mode [`TooBusyShared`].
+///
+/// Each tick spawns a trivial task and times how long it takes to be first polled, which is the
+/// scheduling delay requests are competing against. Samples are taken several times per `interval`
+/// so the window always holds a few observations. The window is owned by the probe, which
+/// publishes its minimum to the [`Codel`] through an atomic, keeping that work off the request
+/// hot path.
+pub(crate) struct CodelProbe {
+    inner: Weak<TooBusyShared>,
+    interval: Duration,
+    /// Recent `(observed_at, latency)` samples, trimmed to the trailing `interval`.
+    window: VecDeque<(Instant, Duration)>,
+}
+
+impl CodelProbe {
+    pub(crate) fn new(inner: Weak<TooBusyShared>, interval: Duration) -> Self {
+        CodelProbe {
+            inner,
+            interval,
+            window: VecDeque::new(),
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        // Sample a handful of times per interval to keep the windowed minimum meaningful.
+        let period = (self.interval / 8).max(Duration::from_millis(1));
+        let start = Instant::now() + period;
+        let mut interval = interval_at(start.into(), period);
+
+        loop {
+            let _ = interval.tick().await;
+            let too_busy_shared = match self.inner.upgrade() {
+                Some(inner) => inner,
+                None => break,
+            };
+
+            if !matches!(too_busy_shared.mode, Mode::Codel(_)) {
+                break;
+            }
+
+            let spawned_at = Instant::now();
+            let latency = tokio::spawn(async move { spawned_at.elapsed() })
+                .await
+                .unwrap_or_default();
+            let min_latency = self.observe(Instant::now(), latency);
+
+            if let Mode::Codel(codel) = &too_busy_shared.mode {
+                codel.publish_min_latency(min_latency);
+            }
+        }
+    }
+
+    /// Record a sample, trim the window to the trailing `interval` and return its minimum latency.
+    fn observe(&mut self, now: Instant, latency: Duration) -> Duration {
+        self.window.push_back((now, latency));
+        while let Some((observed_at, _)) = self.window.front() {
+            if now.duration_since(*observed_at) > self.interval {
+                self.window.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.window
+            .iter()
+            .map(|(_, latency)| *latency)
+            .min()
+            .unwrap_or_default()
+    }
 }
 
 #[cfg(test)]
@@ -116,6 +454,26 @@ mod tests {
     use rand::rngs::mock::StepRng;
     use std::sync::Arc;
 
+    /// Signal reporting a fixed load, used to drive the EWMA deterministically.
+    struct ConstantLoad(f32);
+
+    impl LoadSignal for ConstantLoad {
+        fn sample(&self, _elapsed: Duration) -> f32 {
+            self.0
+        }
+    }
+
+    /// Build a watermark-mode [`TooBusyShared`], filling the prometheus-only `metrics` argument so
+    /// the tests compile in every feature set.
+    fn watermark_shared(low_watermark: u32, high_watermark: u32) -> TooBusyShared {
+        TooBusyShared::new(
+            low_watermark,
+            high_watermark,
+            #[cfg(feature = "prometheus")]
+            None,
+        )
+    }
+
     macro_rules! too_busy_shared_tests {
         ($($name:ident: $value:expr,)*) => {
         $(
@@ -129,10 +487,10 @@ mod tests {
     }
 
     too_busy_shared_tests! {
-        too_busy_shared_tests_below_low_watermark: (TooBusyShared::new(80, 90), &mut StepRng::new(99, 1), 79, false),
-        too_busy_shared_tests_within_watermarks_randomly_false: (TooBusyShared::new(80, 90), &mut StepRng::new(50, 1), 85, false),
-        too_busy_shared_tests_within_watermarks_randomly_true: (TooBusyShared::new(80, 90), &mut StepRng::new(49, 1), 85, true),
-        too_busy_shared_tests_above_high_watermark: (TooBusyShared::new(80, 90), &mut StepRng::new(0, 1), 90, true),
+        too_busy_shared_tests_below_low_watermark: (watermark_shared(80, 90), &mut StepRng::new(99, 1), 79, false),
+        too_busy_shared_tests_within_watermarks_randomly_false: (watermark_shared(80, 90), &mut StepRng::new(50, 1), 85, false),
+        too_busy_shared_tests_within_watermarks_randomly_true: (watermark_shared(80, 90), &mut StepRng::new(49, 1), 85, true),
+        too_busy_shared_tests_above_high_watermark: (watermark_shared(80, 90), &mut StepRng::new(0, 1), 90, true),
     }
 
     macro_rules! load_feeder_tests {
@@ -140,19 +498,152 @@ mod tests {
         $(
             #[test]
             fn $name() {
-                let (ewma_alpha, interval, busy, ratio_busy_ewma, expected) = $value;
-                let inner = Arc::new(TooBusyShared::new(80, 90));
+                let (ewma_alpha, interval, ratio_busy, ratio_busy_ewma, expected) = $value;
+                let inner = Arc::new(watermark_shared(80, 90));
                 let weak_reference = Arc::downgrade(&inner);
-                let load_feeder = LoadFeeder::new(weak_reference, interval, ewma_alpha);
-                assert_eq!(expected, load_feeder.calculate_ratio_busy_ewma(busy.as_millis(), ratio_busy_ewma));
+                let load_feeder = LoadFeeder::new(
+                    weak_reference,
+                    interval,
+                    ewma_alpha,
+                    Box::new(ConstantLoad(ratio_busy)),
+                );
+                assert_eq!(expected, load_feeder.calculate_ratio_busy_ewma(ratio_busy, ratio_busy_ewma));
             }
         )*
         }
     }
 
     load_feeder_tests! {
-        load_feeder_tests_alpha_0_9: (0.9, Duration::from_secs(10), Duration::from_secs(5), 10.0, 14.000001),
-        load_feeder_tests_alpha_0_1: (0.1, Duration::from_secs(10), Duration::from_secs(5), 10.0, 46.0),
-        load_feeder_tests_alpha_max_100: (0.1, Duration::from_secs(10), Duration::from_secs(11), 10.0, 91.0),
+        load_feeder_tests_alpha_0_9: (0.9, Duration::from_secs(10), 50.0, 10.0, 14.000001),
+        load_feeder_tests_alpha_0_1: (0.1, Duration::from_secs(10), 50.0, 10.0, 46.0),
+        load_feeder_tests_alpha_max_100: (0.1, Duration::from_secs(10), 100.0, 10.0, 91.0),
+    }
+
+    use crate::signal::{StepMetricsSource, WorkerBusyDuration};
+
+    // Cumulative per-worker busy millis over a 1s interval: a baseline, three fully-busy
+    // intervals (ramp-up), two idle intervals (decay), then a >100% interval (saturation).
+    fn ramp_decay_saturation_feeder() -> (Arc<TooBusyShared>, LoadFeeder) {
+        let inner = Arc::new(watermark_shared(85, 95));
+        let weak_reference = Arc::downgrade(&inner);
+        let source = StepMetricsSource::new(vec![0, 1000, 2000, 3000, 3000, 3000, 5000]);
+        let signal = Box::new(WorkerBusyDuration::with_source(source));
+        let feeder = LoadFeeder::new(weak_reference, Duration::from_secs(1), 0.1, signal);
+        (inner, feeder)
+    }
+
+    #[test]
+    fn load_feeder_step_trajectory_is_deterministic() {
+        let (inner, feeder) = ramp_decay_saturation_feeder();
+
+        let mut ratio_busy_ewma = 0.0;
+        let mut trajectory = Vec::new();
+        for _ in 0..6 {
+            ratio_busy_ewma = feeder.step(&inner, ratio_busy_ewma);
+            trajectory.push(inner.ratio_busy_ewma());
+        }
+
+        // ramp-up saturates towards 100, decay falls back towards 0, then a single
+        // over-budget interval is clamped and lifts the ratio straight back up.
+        assert_eq!(trajectory, vec![90, 99, 99, 9, 0, 90]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn load_feeder_run_drives_ratio_under_paused_time() {
+        let inner = Arc::new(watermark_shared(85, 95));
+        let weak_reference = Arc::downgrade(&inner);
+        let source = StepMetricsSource::new(vec![0, 1000, 2000, 3000, 4000]);
+        let signal = Box::new(WorkerBusyDuration::with_source(source));
+
+        let handle = tokio::spawn(async move {
+            LoadFeeder::new(weak_reference, Duration::from_secs(1), 0.1, signal)
+                .run()
+                .await;
+        });
+
+        for _ in 0..4 {
+            tokio::time::advance(Duration::from_secs(1)).await;
+            tokio::task::yield_now().await;
+        }
+
+        assert!(inner.ratio_busy_ewma() >= 95);
+        assert!(inner.eval());
+
+        handle.abort();
+    }
+
+    #[test]
+    fn codel_control_law() {
+        let t0 = Instant::now();
+        let mut vars = CodelVars {
+            target: Duration::from_millis(5),
+            interval: Duration::from_millis(100),
+            dropping: false,
+            count: 0,
+            first_above_target: None,
+            drop_next: t0,
+        };
+
+        // Below target: nothing to shed.
+        assert!(!vars.should_drop(t0, Duration::from_millis(1)));
+        // First observation above target only arms the timer.
+        assert!(!vars.should_drop(t0, Duration::from_millis(10)));
+        // Still within the interval: keep admitting.
+        assert!(!vars.should_drop(t0 + Duration::from_millis(50), Duration::from_millis(10)));
+
+        // Sustained above target for a full interval: enter dropping state.
+        let entered = t0 + Duration::from_millis(100);
+        assert!(vars.should_drop(entered, Duration::from_millis(10)));
+        assert_eq!(vars.count, 1);
+
+        // Before the next scheduled drop we keep admitting.
+        assert!(!vars.should_drop(entered + Duration::from_millis(1), Duration::from_millis(10)));
+
+        // Reaching the scheduled drop sheds again and ramps the cadence up.
+        let drop_next = vars.drop_next;
+        assert!(vars.should_drop(drop_next, Duration::from_millis(10)));
+        assert_eq!(vars.count, 2);
+
+        // Latency recovers below target: leave dropping and reset the cadence.
+        assert!(!vars.should_drop(drop_next + Duration::from_millis(1), Duration::from_millis(1)));
+        assert!(!vars.dropping);
+        assert_eq!(vars.count, 0);
+    }
+
+    #[test]
+    fn codel_probe_window_keeps_trailing_interval_minimum() {
+        let inner = Arc::new(watermark_shared(85, 95));
+        let mut probe = CodelProbe::new(Arc::downgrade(&inner), Duration::from_millis(100));
+        let t0 = Instant::now();
+
+        assert_eq!(
+            probe.observe(t0, Duration::from_millis(20)),
+            Duration::from_millis(20)
+        );
+        assert_eq!(
+            probe.observe(t0 + Duration::from_millis(10), Duration::from_millis(8)),
+            Duration::from_millis(8)
+        );
+
+        // A sample a full interval later prunes the stale observations.
+        assert_eq!(
+            probe.observe(t0 + Duration::from_millis(200), Duration::from_millis(30)),
+            Duration::from_millis(30)
+        );
+    }
+
+    #[test]
+    fn codel_publishes_min_latency_to_eval() {
+        let codel = Codel::new(Duration::from_millis(5), Duration::from_millis(100));
+        let now = Instant::now();
+
+        // Below target: admit without arming the drop timer.
+        codel.publish_min_latency(Duration::from_millis(1));
+        assert!(!codel.eval(now));
+
+        // Above target but only just observed: still admit until a full interval elapses.
+        codel.publish_min_latency(Duration::from_millis(10));
+        assert!(!codel.eval(now));
+        assert!(codel.eval(now + Duration::from_millis(100)));
     }
 }
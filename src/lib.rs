@@ -48,10 +48,17 @@
 //! [worker_total_busy_duration]: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.worker_total_busy_duration
 //! [Runtime]: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html
 mod inner;
-use crate::inner::{LoadFeeder, TooBusyShared};
+mod signal;
+#[cfg(feature = "prometheus")]
+use crate::inner::Metrics;
+use crate::inner::{CodelProbe, LoadFeeder, TooBusyShared};
 use std::sync::Arc;
 use tokio::time::Duration;
 
+pub use crate::signal::{LoadSignal, MaxLoad, SystemLoad, WorkerBusyDuration};
+#[cfg(tokio_unstable)]
+pub use crate::signal::{PollLatencyLoad, QueueDepthLoad};
+
 /// Track how busy are the tokio workers.
 ///
 /// This type is internally reference-counted and can be freely cloned.
@@ -108,6 +115,10 @@ pub struct TooBusyBuilder {
     low_watermark: u32,
     high_watermark: u32,
     ewma_alpha: f32,
+    signal: Option<Box<dyn LoadSignal>>,
+    codel: Option<Duration>,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Metrics>,
 }
 
 impl TooBusyBuilder {
@@ -152,13 +163,93 @@ impl TooBusyBuilder {
         self.ewma_alpha = ewma_alpha;
         self
     }
+    /// Register the shedding metrics into a Prometheus `registry` under `namespace`.
+    ///
+    /// Once registered, the busy ratio is exported as a gauge and every [`TooBusy::eval`]
+    /// increments either a shed or an admitted counter, so operators can scrape how
+    /// aggressively the middleware is rejecting traffic.
+    #[cfg(feature = "prometheus")]
+    pub fn register(
+        mut self,
+        registry: &prometheus::Registry,
+        namespace: &str,
+    ) -> prometheus::Result<TooBusyBuilder> {
+        self.metrics = Some(Metrics::register(registry, namespace)?);
+        Ok(self)
+    }
+
+    /// Set the load signal feeding the busy ratio.
+    ///
+    /// By default the busy ratio is derived from [`WorkerBusyDuration`] when built with
+    /// `--cfg tokio_unstable`, and from [`SystemLoad`] otherwise. Supply [`SystemLoad`] to shed
+    /// from OS-level CPU (and optionally memory) pressure, or a [`QueueDepthLoad`] / [`PollLatencyLoad`]
+    /// signal, or a [`MaxLoad`] combinator taking the worst across several of them. The resulting
+    /// `0`–`100` value flows through the same EWMA and watermark machinery, so [`TooBusy::eval`]
+    /// semantics are unchanged regardless of the signal.
+    pub fn signal(mut self, signal: impl LoadSignal) -> TooBusyBuilder {
+        self.signal = Some(Box::new(signal));
+        self
+    }
+
+    /// Shed using the CoDel strategy with the default 5ms target delay.
+    ///
+    /// See [`codel_target`](TooBusyBuilder::codel_target) for the semantics; this is a shorthand
+    /// for the commonly used 5ms target.
+    pub fn codel(self) -> TooBusyBuilder {
+        self.codel_target(Duration::from_millis(5))
+    }
+
+    /// Shed using the CoDel strategy targeting `target` scheduling delay.
+    ///
+    /// In CoDel mode [`TooBusy::eval`] sheds based on the controlled delay clients experience
+    /// rather than the watermark ramp: a background probe measures the scheduling latency and,
+    /// once its minimum over a full [`interval`](TooBusyBuilder::interval) stays above `target`,
+    /// requests start being dropped at an accelerating `interval / sqrt(count)` cadence until the
+    /// latency recovers. This targets the queueing delay directly, which behaves better than a
+    /// CPU-busy ramp under bursty backlogs.
+    ///
+    /// CoDel mode takes precedence over the watermark machinery: when it is selected the
+    /// configured [`signal`](TooBusyBuilder::signal), [`ewma_alpha`](TooBusyBuilder::ewma_alpha)
+    /// and watermarks are unused, since shedding is driven by scheduling latency rather than the
+    /// busy ratio. Only [`interval`](TooBusyBuilder::interval) is shared between the two modes.
+    pub fn codel_target(mut self, target: Duration) -> TooBusyBuilder {
+        self.codel = Some(target);
+        self
+    }
+
     /// Build the [`TooBusy`] instance.
     pub fn build(self) -> TooBusy {
-        let inner = Arc::new(TooBusyShared::new(self.low_watermark, self.high_watermark));
+        let interval = self.interval;
+
+        if let Some(target) = self.codel {
+            let inner = Arc::new(TooBusyShared::new_codel(
+                target,
+                interval,
+                #[cfg(feature = "prometheus")]
+                self.metrics,
+            ));
+            let weak_reference = Arc::downgrade(&inner);
+
+            tokio::spawn(async move {
+                CodelProbe::new(weak_reference, interval).run().await;
+            });
+
+            return TooBusy { inner };
+        }
+
+        let inner = Arc::new(TooBusyShared::new(
+            self.low_watermark,
+            self.high_watermark,
+            #[cfg(feature = "prometheus")]
+            self.metrics,
+        ));
         let weak_reference = Arc::downgrade(&inner);
 
+        let ewma_alpha = self.ewma_alpha;
+        let signal: Box<dyn LoadSignal> = self.signal.unwrap_or_else(default_signal);
+
         tokio::spawn(async move {
-            LoadFeeder::new(weak_reference, self.interval, self.ewma_alpha)
+            LoadFeeder::new(weak_reference, interval, ewma_alpha, signal)
                 .run()
                 .await;
         });
@@ -167,6 +258,18 @@ impl TooBusyBuilder {
     }
 }
 
+/// Default load signal when none is configured: the runtime busy-duration sampler on
+/// `--cfg tokio_unstable`, otherwise the OS-level [`SystemLoad`] so the builder works on stable.
+#[cfg(tokio_unstable)]
+fn default_signal() -> Box<dyn LoadSignal> {
+    Box::new(WorkerBusyDuration::new())
+}
+
+#[cfg(not(tokio_unstable))]
+fn default_signal() -> Box<dyn LoadSignal> {
+    Box::new(SystemLoad::new())
+}
+
 impl Default for TooBusyBuilder {
     fn default() -> Self {
         TooBusyBuilder {
@@ -174,6 +277,10 @@ impl Default for TooBusyBuilder {
             low_watermark: 85,
             high_watermark: 95,
             ewma_alpha: 0.1,
+            signal: None,
+            codel: None,
+            #[cfg(feature = "prometheus")]
+            metrics: None,
         }
     }
 }
@@ -0,0 +1,414 @@
+//! Pluggable load signals feeding the busy ratio.
+//!
+//! A [`LoadSignal`] turns whatever the runtime exposes into a single load percentage in the
+//! `[0, 100]` range that the [`LoadFeeder`](crate::inner::LoadFeeder) smooths through the EWMA
+//! and compares against the watermarks. [`WorkerBusyDuration`], the default under
+//! `--cfg tokio_unstable`, keeps the original behavior of tracking
+//! [`worker_total_busy_duration`]; [`SystemLoad`] is the default otherwise. The other samplers target the
+//! richer runtime metrics that tokio-metrics-style collectors expose so that a server starved by
+//! a deep backlog rather than raw CPU time can still shed correctly.
+//!
+//! [`worker_total_busy_duration`]: tokio::runtime::RuntimeMetrics::worker_total_busy_duration
+
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(tokio_unstable)]
+use tokio::runtime::Handle;
+use tokio::runtime::RuntimeMetrics;
+use tokio::time::Duration;
+
+/// A source of load expressed as a percentage in the `[0, 100]` range.
+///
+/// Implementations are sampled once per [`interval`](crate::TooBusyBuilder::interval); the
+/// `elapsed` argument is the time since the previous sample, which samplers accumulating a delta
+/// (such as a busy duration) use to normalize their measurement.
+pub trait LoadSignal: Send + 'static {
+    /// Sample the current load as a percentage in the `[0, 100]` range.
+    fn sample(&self, elapsed: Duration) -> f32;
+}
+
+impl LoadSignal for Box<dyn LoadSignal> {
+    fn sample(&self, elapsed: Duration) -> f32 {
+        (**self).sample(elapsed)
+    }
+}
+
+/// Source of the per-worker busy durations feeding [`WorkerBusyDuration`].
+///
+/// The real implementation wraps the runtime's [`RuntimeMetrics`], while tests can supply a
+/// synthetic source that replays a fixed busy-duration sequence so the feedback loop can be
+/// exercised without real sleeps.
+pub trait MetricsSource: Send + 'static {
+    /// Number of workers the runtime is scheduling onto.
+    fn num_workers(&self) -> usize;
+    /// Cumulative busy duration of `worker` since the runtime started.
+    fn worker_total_busy_duration(&self, worker: usize) -> Duration;
+}
+
+#[cfg(tokio_unstable)]
+impl MetricsSource for RuntimeMetrics {
+    fn num_workers(&self) -> usize {
+        RuntimeMetrics::num_workers(self)
+    }
+    fn worker_total_busy_duration(&self, worker: usize) -> Duration {
+        RuntimeMetrics::worker_total_busy_duration(self, worker)
+    }
+}
+
+/// Default signal tracking the busy duration accumulated by the Tokio workers.
+///
+/// Each sample averages [`worker_total_busy_duration`] across the workers and reports the fraction
+/// of `elapsed` the workers spent busy since the previous sample. It is generic over a
+/// [`MetricsSource`] so tests can feed synthetic busy-duration sequences.
+///
+/// Requires Tokio to be compiled with `--cfg tokio_unstable`.
+///
+/// [`worker_total_busy_duration`]: tokio::runtime::RuntimeMetrics::worker_total_busy_duration
+pub struct WorkerBusyDuration<S = RuntimeMetrics> {
+    metrics: S,
+    num_workers: u32,
+    latest_total_busy: AtomicU64,
+}
+
+#[cfg(tokio_unstable)]
+impl WorkerBusyDuration<RuntimeMetrics> {
+    /// Build the signal from the current runtime. Must be called within a Tokio runtime.
+    pub fn new() -> Self {
+        Self::with_source(Handle::current().metrics())
+    }
+}
+
+impl<S: MetricsSource> WorkerBusyDuration<S> {
+    /// Build the signal from an explicit [`MetricsSource`].
+    pub fn with_source(metrics: S) -> Self {
+        let num_workers = metrics.num_workers() as u32;
+        let latest_total_busy = AtomicU64::new(Self::total_busy_millis(&metrics, num_workers));
+        WorkerBusyDuration {
+            metrics,
+            num_workers,
+            latest_total_busy,
+        }
+    }
+
+    fn total_busy_millis(metrics: &S, num_workers: u32) -> u64 {
+        ((0..num_workers as usize)
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .sum::<Duration>()
+            / num_workers)
+            .as_millis() as u64
+    }
+
+    /// Normalize a busy delta against the elapsed time, clamped to `[0, 100]`.
+    fn ratio(busy: u128, elapsed: Duration) -> f32 {
+        f32::min(100.0, (busy as f32 / elapsed.as_millis() as f32) * 100.0)
+    }
+}
+
+#[cfg(tokio_unstable)]
+impl Default for WorkerBusyDuration<RuntimeMetrics> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: MetricsSource> LoadSignal for WorkerBusyDuration<S> {
+    fn sample(&self, elapsed: Duration) -> f32 {
+        let total = Self::total_busy_millis(&self.metrics, self.num_workers);
+        let latest = self.latest_total_busy.swap(total, Ordering::Relaxed);
+        Self::ratio((total - latest) as u128, elapsed)
+    }
+}
+
+/// Signal driven by how many tasks are waiting to be polled.
+///
+/// Each sample sums the global injection queue depth and every worker's local queue depth, then
+/// normalizes against the worker count: one queued task per worker is treated as fully loaded.
+///
+/// Requires Tokio to be compiled with `--cfg tokio_unstable`.
+#[cfg(tokio_unstable)]
+pub struct QueueDepthLoad {
+    metrics: RuntimeMetrics,
+    num_workers: u32,
+}
+
+#[cfg(tokio_unstable)]
+impl QueueDepthLoad {
+    /// Build the signal from the current runtime. Must be called within a Tokio runtime.
+    pub fn new() -> Self {
+        let metrics = Handle::current().metrics();
+        let num_workers = metrics.num_workers() as u32;
+        QueueDepthLoad {
+            metrics,
+            num_workers,
+        }
+    }
+
+    /// Map a total queue depth to a load percentage, clamped to `[0, 100]`.
+    fn ratio(total_depth: usize, num_workers: u32) -> f32 {
+        f32::min(100.0, (total_depth as f32 / num_workers as f32) * 100.0)
+    }
+}
+
+#[cfg(tokio_unstable)]
+impl Default for QueueDepthLoad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(tokio_unstable)]
+impl LoadSignal for QueueDepthLoad {
+    fn sample(&self, _elapsed: Duration) -> f32 {
+        let local: usize = (0..self.num_workers as usize)
+            .map(|worker| self.metrics.worker_local_queue_depth(worker))
+            .sum();
+        Self::ratio(self.metrics.global_queue_depth() + local, self.num_workers)
+    }
+}
+
+/// Signal driven by the mean time tasks spend being polled.
+///
+/// Each sample averages [`worker_mean_poll_time`] across the workers and reports it relative to a
+/// saturation latency, at or above which the workers are considered fully loaded.
+///
+/// Requires Tokio to be compiled with `--cfg tokio_unstable`.
+///
+/// [`worker_mean_poll_time`]: tokio::runtime::RuntimeMetrics::worker_mean_poll_time
+#[cfg(tokio_unstable)]
+pub struct PollLatencyLoad {
+    metrics: RuntimeMetrics,
+    num_workers: u32,
+    saturation: Duration,
+}
+
+#[cfg(tokio_unstable)]
+impl PollLatencyLoad {
+    /// Build the signal from the current runtime, saturating at `saturation`.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn new(saturation: Duration) -> Self {
+        let metrics = Handle::current().metrics();
+        let num_workers = metrics.num_workers() as u32;
+        PollLatencyLoad {
+            metrics,
+            num_workers,
+            saturation,
+        }
+    }
+
+    /// Map a mean poll latency to a load percentage, clamped to `[0, 100]`.
+    fn ratio(mean: Duration, saturation: Duration) -> f32 {
+        f32::min(
+            100.0,
+            (mean.as_secs_f32() / saturation.as_secs_f32()) * 100.0,
+        )
+    }
+}
+
+#[cfg(tokio_unstable)]
+impl LoadSignal for PollLatencyLoad {
+    fn sample(&self, _elapsed: Duration) -> f32 {
+        let mean = (0..self.num_workers as usize)
+            .map(|worker| self.metrics.worker_mean_poll_time(worker))
+            .sum::<Duration>()
+            / self.num_workers;
+        Self::ratio(mean, self.saturation)
+    }
+}
+
+/// Signal backed by the OS-level CPU utilization of the current process.
+///
+/// Unlike the runtime-metrics samplers this works on stable Tokio without `--cfg tokio_unstable`:
+/// each sample refreshes the process' CPU percentage through a sysinfo [`System`] and normalizes
+/// it against the host's CPU count. Memory pressure can optionally be folded in, in which case the
+/// reported load is the worst of the two dimensions.
+///
+/// [`System`]: sysinfo::System
+pub struct SystemLoad {
+    system: std::sync::Mutex<sysinfo::System>,
+    pid: sysinfo::Pid,
+    with_memory: bool,
+}
+
+impl SystemLoad {
+    /// Build the signal for the current process. Panics if the process id cannot be resolved.
+    pub fn new() -> Self {
+        let pid = sysinfo::get_current_pid().expect("current process id is available");
+        let mut system = sysinfo::System::new();
+        system.refresh_cpu();
+        system.refresh_process(pid);
+        SystemLoad {
+            system: std::sync::Mutex::new(system),
+            pid,
+            with_memory: false,
+        }
+    }
+
+    /// Also fold the process' memory pressure into the reported load.
+    ///
+    /// The load becomes the maximum of the CPU utilization and the fraction of the host's memory
+    /// the process is holding, so either dimension can trigger shedding on its own.
+    pub fn with_memory_pressure(mut self) -> Self {
+        self.with_memory = true;
+        self
+    }
+
+    /// Combine a raw process CPU usage and memory fraction into a load percentage in `[0, 100]`.
+    fn ratio(cpu_usage: f32, num_cpus: usize, mem_fraction: f32, with_memory: bool) -> f32 {
+        let cpu = f32::min(100.0, cpu_usage / num_cpus.max(1) as f32);
+        if with_memory {
+            f32::max(cpu, f32::min(100.0, mem_fraction * 100.0))
+        } else {
+            cpu
+        }
+    }
+}
+
+impl Default for SystemLoad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoadSignal for SystemLoad {
+    fn sample(&self, _elapsed: Duration) -> f32 {
+        let mut system = self.system.lock().unwrap();
+        system.refresh_cpu();
+        system.refresh_process(self.pid);
+
+        let num_cpus = system.cpus().len();
+        let process = match system.process(self.pid) {
+            Some(process) => process,
+            None => return 0.0,
+        };
+        let cpu_usage = process.cpu_usage();
+        let mem_fraction = if self.with_memory {
+            process.memory() as f32 / system.total_memory().max(1) as f32
+        } else {
+            0.0
+        };
+        Self::ratio(cpu_usage, num_cpus, mem_fraction, self.with_memory)
+    }
+}
+
+/// Combinator reporting the maximum load across several signals.
+///
+/// Useful when a server can be overloaded through more than one dimension: it sheds as soon as
+/// any of the underlying signals considers the workers too busy.
+pub struct MaxLoad {
+    signals: Vec<Box<dyn LoadSignal>>,
+}
+
+impl MaxLoad {
+    /// Build a combinator taking the maximum across `signals`.
+    pub fn new(signals: Vec<Box<dyn LoadSignal>>) -> Self {
+        MaxLoad { signals }
+    }
+}
+
+impl LoadSignal for MaxLoad {
+    fn sample(&self, elapsed: Duration) -> f32 {
+        self.signals
+            .iter()
+            .map(|signal| signal.sample(elapsed))
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Synthetic [`MetricsSource`] replaying a fixed sequence of cumulative busy durations.
+///
+/// Each call to [`sample`](LoadSignal::sample) advances one step through the sequence, so tests
+/// can drive the feedback loop with a deterministic busy-duration trajectory. Shared with the
+/// feeder tests in [`crate::inner`].
+#[cfg(test)]
+pub(crate) struct StepMetricsSource {
+    busy_millis: Vec<u64>,
+    cursor: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl StepMetricsSource {
+    /// Build a single-worker source replaying `busy_millis` as cumulative busy durations.
+    pub(crate) fn new(busy_millis: Vec<u64>) -> Self {
+        StepMetricsSource {
+            busy_millis,
+            cursor: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MetricsSource for StepMetricsSource {
+    fn num_workers(&self) -> usize {
+        1
+    }
+    fn worker_total_busy_duration(&self, _worker: usize) -> Duration {
+        let idx = self.cursor.fetch_add(1, Ordering::Relaxed);
+        Duration::from_millis(self.busy_millis[idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(tokio_unstable)]
+    #[test]
+    fn worker_busy_duration_ratio() {
+        assert_eq!(
+            50.0,
+            WorkerBusyDuration::<RuntimeMetrics>::ratio(
+                Duration::from_secs(5).as_millis(),
+                Duration::from_secs(10)
+            )
+        );
+    }
+
+    #[cfg(tokio_unstable)]
+    #[test]
+    fn worker_busy_duration_ratio_saturates_at_100() {
+        assert_eq!(
+            100.0,
+            WorkerBusyDuration::<RuntimeMetrics>::ratio(
+                Duration::from_secs(11).as_millis(),
+                Duration::from_secs(10)
+            )
+        );
+    }
+
+    #[cfg(tokio_unstable)]
+    #[test]
+    fn queue_depth_ratio_saturates_at_one_task_per_worker() {
+        assert_eq!(50.0, QueueDepthLoad::ratio(2, 4));
+        assert_eq!(100.0, QueueDepthLoad::ratio(4, 4));
+        assert_eq!(100.0, QueueDepthLoad::ratio(8, 4));
+    }
+
+    #[cfg(tokio_unstable)]
+    #[test]
+    fn poll_latency_ratio_saturates_at_saturation() {
+        assert_eq!(
+            50.0,
+            PollLatencyLoad::ratio(Duration::from_millis(5), Duration::from_millis(10))
+        );
+        assert_eq!(
+            100.0,
+            PollLatencyLoad::ratio(Duration::from_millis(20), Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn system_load_ratio_normalizes_cpu_against_core_count() {
+        // 200% across 4 cores is half the machine.
+        assert_eq!(50.0, SystemLoad::ratio(200.0, 4, 0.0, false));
+        // Usage reported beyond the core count is still clamped to 100%.
+        assert_eq!(100.0, SystemLoad::ratio(800.0, 4, 0.0, false));
+    }
+
+    #[test]
+    fn system_load_ratio_folds_in_memory_pressure() {
+        // Idle CPU but 80% memory held -> memory dominates.
+        assert_eq!(80.0, SystemLoad::ratio(0.0, 4, 0.8, true));
+        // Memory ignored unless explicitly enabled.
+        assert_eq!(0.0, SystemLoad::ratio(0.0, 4, 0.8, false));
+    }
+}